@@ -5,6 +5,10 @@ pub type ArcAny = Arc<Any + Send + Sync>;
 
 /// `LogVar` is used by `Log` to track which `Var` was either read or written or both.
 /// Depending on the type, STM has to write, ensure consistency or block on this value.
+///
+/// `Var::get_value` reads a `Var` without a shared mutex, and cloning the
+/// `ArcAny` values stored here on the `Read`/`ReadWrite` paths below is
+/// only an `Arc` clone, so it never touches a shared lock either.
 #[derive(Clone)]
 pub enum LogVar {
     /// Var has been read.
@@ -0,0 +1,404 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use atomic_arc_cell::AtomicArcCell;
+use stm_core::sharded_lock;
+use stm_core::transaction::{AsyncControlBlock, ControlBlock, WaitResult};
+#[cfg(feature = "deadlock_detection")]
+use stm_core::deadlock::{self, VarId};
+
+/// A transactional variable.
+///
+/// The current value lives behind an `AtomicArcCell`, so reads never take
+/// a mutex; they only enter the calling thread's own slot of the shared
+/// `ShardedRwLock`. Writers still only publish a new value while holding the commit
+/// lock that serializes commits across `Var`s.
+pub struct Var<T> {
+    value: AtomicArcCell<T>,
+
+    /// Transactions blocked in `retry`, woken on every write.
+    waiters: Mutex<Vec<Weak<ControlBlock>>>,
+
+    /// Tasks suspended in `retry_async`, woken on every write.
+    async_waiters: Mutex<Vec<Weak<AsyncControlBlock>>>,
+
+    /// Stable id used to register this var in the deadlock-detection
+    /// wait-for graph.
+    #[cfg(feature = "deadlock_detection")]
+    id: VarId,
+}
+
+impl<T> Var<T>
+where
+    T: Send + Sync + 'static,
+{
+    /// Create a new `Var` holding `val`.
+    pub fn new(val: T) -> Var<T> {
+        Var {
+            value: AtomicArcCell::new(Arc::new(val)),
+            waiters: Mutex::new(Vec::new()),
+            async_waiters: Mutex::new(Vec::new()),
+            #[cfg(feature = "deadlock_detection")]
+            id: deadlock::new_var_id(),
+        }
+    }
+
+    /// Read the current value without touching a shared mutex.
+    ///
+    /// Entering the shared `ShardedRwLock`'s read region lets a concurrent
+    /// `set_value` know a read is in flight, without this thread contending
+    /// with any other reader thread's slot. The read waits only while a
+    /// `set_value` holds the writer flag.
+    pub fn get_value(&self) -> Arc<T> {
+        let _guard = sharded_lock::global().enter_read();
+        self.value.load()
+    }
+
+    /// Publish a new value.
+    ///
+    /// Claims ownership of this var for deadlock detection, then takes the
+    /// shared `ShardedRwLock`'s commit lock: this also serializes against
+    /// every other `Var`'s concurrent `set_value`, since they all commit
+    /// through the same global lock, and waits for every reader slot to
+    /// quiesce before publishing. Wakes any transaction blocked in `retry`
+    /// or `retry_async` on this var once the new value is visible.
+    pub fn set_value(&self, val: Arc<T>) {
+        #[cfg(feature = "deadlock_detection")]
+        deadlock::claim_owner(self.id);
+
+        let _write_guard = sharded_lock::global().begin_write();
+        self.value.store(val);
+        self.wake_waiters();
+        self.wake_async_waiters();
+    }
+
+    /// Block the calling thread until this var changes from `last_observed`,
+    /// then return its new value.
+    ///
+    /// `last_observed` is whatever value the caller read before deciding it
+    /// needed to retry (e.g. via `get_value`). If the var has already moved
+    /// on from it by the time `retry` is called, this returns immediately
+    /// instead of waiting for some further write that may never come;
+    /// otherwise it registers a `ControlBlock`, with the deadlock-detection
+    /// registry if enabled, and parks until `set_value` wakes it.
+    pub fn retry(&self, last_observed: &Arc<T>) -> Arc<T> {
+        let ctrl = Arc::new(ControlBlock::new());
+        #[cfg(feature = "deadlock_detection")]
+        ctrl.register_wait(&[self.id]);
+        self.waiters.lock().unwrap().push(Arc::downgrade(&ctrl));
+
+        // Register first, then check: a concurrent `set_value` between
+        // registering and this check still wakes `ctrl` via the waiters
+        // list, and `ctrl.wait()` below returns immediately either way.
+        if !Arc::ptr_eq(&self.get_value(), last_observed) {
+            ctrl.set_changed();
+        }
+
+        ctrl.wait();
+        self.get_value()
+    }
+
+    /// Block until this var changes from `last_observed` or `timeout`
+    /// elapses, whichever comes first.
+    ///
+    /// The bounded counterpart to `retry`: returns the new value, or
+    /// `None` if the deadline passed without a write, instead of blocking
+    /// forever. Resolves immediately, like `retry`, if the var has already
+    /// moved on from `last_observed`.
+    pub fn retry_timeout(&self, last_observed: &Arc<T>, timeout: Duration) -> Option<Arc<T>> {
+        let ctrl = Arc::new(ControlBlock::new());
+        #[cfg(feature = "deadlock_detection")]
+        ctrl.register_wait(&[self.id]);
+        self.waiters.lock().unwrap().push(Arc::downgrade(&ctrl));
+
+        if !Arc::ptr_eq(&self.get_value(), last_observed) {
+            ctrl.set_changed();
+        }
+
+        match ctrl.wait_deadline(Instant::now() + timeout) {
+            WaitResult::Changed => Some(self.get_value()),
+            WaitResult::TimedOut => None,
+        }
+    }
+
+    /// Suspend the calling task until this var changes from `last_observed`,
+    /// then resolve with its new value.
+    ///
+    /// The async counterpart to a blocking `retry`: registers an
+    /// `AsyncControlBlock` that `set_value` wakes once a new value is
+    /// published, so the executor can run other tasks in the meantime
+    /// instead of parking a whole thread. Like `retry`, resolves
+    /// immediately if the var has already moved on from `last_observed` by
+    /// the time this is called, instead of waiting for a write that
+    /// already happened.
+    pub fn retry_async(&self, last_observed: &Arc<T>) -> RetryAsync<'_, T> {
+        let ctrl = Arc::new(AsyncControlBlock::new());
+        self.async_waiters.lock().unwrap().push(Arc::downgrade(&ctrl));
+
+        if !Arc::ptr_eq(&self.get_value(), last_observed) {
+            ctrl.set_changed();
+        }
+
+        RetryAsync { var: self, ctrl }
+    }
+
+    /// Check whether the live value is still the same allocation as
+    /// `original`.
+    ///
+    /// This is the commit-time consistency check: a `ReadWrite`/`Read`
+    /// entry in the log is only valid to commit if nothing else changed
+    /// the `Var` since it was read. Comparing `Arc` pointers is enough,
+    /// since every write publishes a fresh `Arc`, and it only costs the
+    /// `get_value` read above, not a mutex.
+    pub fn is_still_valid(&self, original: &Arc<T>) -> bool {
+        Arc::ptr_eq(&self.get_value(), original)
+    }
+
+    fn wake_waiters(&self) {
+        for waiter in self.waiters.lock().unwrap().drain(..) {
+            if let Some(ctrl) = waiter.upgrade() {
+                ctrl.set_changed();
+            }
+        }
+    }
+
+    fn wake_async_waiters(&self) {
+        for waiter in self.async_waiters.lock().unwrap().drain(..) {
+            if let Some(ctrl) = waiter.upgrade() {
+                ctrl.set_changed();
+            }
+        }
+    }
+}
+
+/// Future returned by `Var::retry_async`.
+///
+/// Resolves with the var's new value once something calls `set_value`.
+pub struct RetryAsync<'a, T> {
+    var: &'a Var<T>,
+    ctrl: Arc<AsyncControlBlock>,
+}
+
+impl<'a, T> Future for RetryAsync<'a, T>
+where
+    T: Send + Sync + 'static,
+{
+    type Output = Arc<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Arc<T>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.ctrl.wait()).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(this.var.get_value()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Suspend on `var`'s `retry_async` instead of parking a thread until it
+/// holds a new value after `last_observed`, then resolve with that value.
+///
+/// This is the async/await entry point: the low-level primitive is
+/// `AsyncControlBlock`, and `atomically_async` is where a caller actually
+/// drives it to get a `Var`'s next value. It waits on this one `Var` only
+/// and runs no transaction body of its own.
+pub async fn atomically_async<T>(var: &Var<T>, last_observed: &Arc<T>) -> Arc<T>
+where
+    T: Send + Sync + 'static,
+{
+    var.retry_async(last_observed).await
+}
+
+/// Block on `var` until it changes from `last_observed`, or return `None`
+/// once `timeout` elapses without such a write.
+///
+/// The bounded counterpart to a blocking `atomically`/`retry`: the
+/// low-level primitive is `ControlBlock::wait_deadline`, and
+/// `atomically_or_timeout` is where a caller actually uses it to get a
+/// `Var`'s next value within a deadline.
+pub fn atomically_or_timeout<T>(var: &Var<T>, last_observed: &Arc<T>, timeout: Duration) -> Option<Arc<T>>
+where
+    T: Send + Sync + 'static,
+{
+    var.retry_timeout(last_observed, timeout)
+}
+
+// TESTS
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+    use std::thread;
+
+    /// A minimal no-op waker, good enough to drive a single poll by hand
+    /// without pulling in an executor.
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            raw_waker()
+        }
+        fn wake(_: *const ()) {}
+        fn wake_by_ref(_: *const ()) {}
+        fn drop(_: *const ()) {}
+
+        fn raw_waker() -> RawWaker {
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+
+        unsafe { Waker::from_raw(raw_waker()) }
+    }
+
+    /// A freshly created `Var` reads back the value it was given.
+    #[test]
+    fn get_value_returns_initial_value() {
+        let var = Var::new(42);
+        assert_eq!(*var.get_value(), 42);
+    }
+
+    /// `is_still_valid` is true until something else writes the `Var`.
+    #[test]
+    fn is_still_valid_detects_concurrent_write() {
+        let var = Var::new(1);
+        let original = var.get_value();
+        assert!(var.is_still_valid(&original));
+
+        var.set_value(Arc::new(2));
+        assert!(!var.is_still_valid(&original));
+    }
+
+    /// `retry` blocks until another thread writes the var, then returns
+    /// the new value.
+    #[test]
+    fn retry_blocks_until_write() {
+        let var = Arc::new(Var::new(1));
+        let var2 = var.clone();
+        let before = var.get_value();
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let value = var2.retry(&before);
+            let _ = tx.send(value);
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        var.set_value(Arc::new(2));
+
+        let result = rx
+            .recv_timeout(Duration::from_millis(500))
+            .expect("retry did not wake up in time");
+        assert_eq!(*result, 2);
+    }
+
+    /// `retry` returns immediately, without blocking, if the var already
+    /// moved on from `last_observed` before `retry` was even called.
+    #[test]
+    fn retry_ready_if_already_changed() {
+        let var = Var::new(1);
+        let before = var.get_value();
+        var.set_value(Arc::new(2));
+
+        assert_eq!(*var.retry(&before), 2);
+    }
+
+    /// `retry_timeout` gives up and returns `None` if nothing writes the
+    /// var before the deadline.
+    #[test]
+    fn retry_timeout_times_out_without_write() {
+        let var = Var::new(1);
+        let before = var.get_value();
+        assert!(var.retry_timeout(&before, Duration::from_millis(50)).is_none());
+    }
+
+    /// `retry_timeout` returns the new value if the var is written before
+    /// the deadline.
+    #[test]
+    fn retry_timeout_returns_new_value_on_write() {
+        let var = Arc::new(Var::new(1));
+        let var2 = var.clone();
+        let before = var.get_value();
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let value = var2.retry_timeout(&before, Duration::from_millis(500));
+            let _ = tx.send(value);
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        var.set_value(Arc::new(2));
+
+        let result = rx
+            .recv_timeout(Duration::from_millis(500))
+            .expect("retry_timeout did not return in time");
+        assert_eq!(*result.expect("expected Some(new value)"), 2);
+    }
+
+    /// A `retry_async` started with a `last_observed` that the var has
+    /// already moved on from resolves immediately, even though the write
+    /// happened before the future was even created.
+    #[test]
+    fn retry_async_ready_if_already_changed() {
+        let var = Var::new(1);
+        let before = var.get_value();
+        var.set_value(Arc::new(2));
+
+        let mut fut = var.retry_async(&before);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        match Pin::new(&mut fut).poll(&mut cx) {
+            Poll::Ready(v) => assert_eq!(*v, 2),
+            Poll::Pending => panic!("expected the future to be ready"),
+        }
+    }
+
+    /// `retry_async` stays pending until `set_value` publishes a value
+    /// different from `last_observed`, then resolves with it.
+    #[test]
+    fn retry_async_wakes_on_write() {
+        let var = Arc::new(Var::new(1));
+        let before = var.get_value();
+        let mut fut = var.retry_async(&before);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Pending);
+
+        var.set_value(Arc::new(2));
+        match Pin::new(&mut fut).poll(&mut cx) {
+            Poll::Ready(v) => assert_eq!(*v, 2),
+            Poll::Pending => panic!("expected the future to be ready"),
+        }
+    }
+
+    /// `retry_async`'s waiter registration also wakes up a task polling
+    /// on another thread.
+    #[test]
+    fn retry_async_wakes_threaded_writer() {
+        let var = Arc::new(Var::new(1));
+        let var2 = var.clone();
+        let before = var.get_value();
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut fut = var2.retry_async(&before);
+            let waker = noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            loop {
+                if let Poll::Ready(v) = Pin::new(&mut fut).poll(&mut cx) {
+                    let _ = tx.send(v);
+                    break;
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        var.set_value(Arc::new(2));
+
+        let result = rx
+            .recv_timeout(Duration::from_millis(500))
+            .expect("retry_async did not resolve in time");
+        assert_eq!(*result, 2);
+    }
+}
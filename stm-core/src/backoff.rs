@@ -0,0 +1,114 @@
+// Copyright 2015-2016 rust-stm Developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::thread;
+
+/// Number of `spin()` steps after which the spin count stops growing.
+const SPIN_LIMIT: u32 = 6;
+
+/// Number of steps after which `is_completed()` reports true.
+///
+/// `snooze()` already switches from spinning to yielding the thread once
+/// the step count passes `SPIN_LIMIT`.
+const YIELD_LIMIT: u32 = 10;
+
+/// Performs exponential backoff for a thread spinning while waiting to
+/// acquire a commit lock, such as `ShardedRwLock::begin_write` waiting for
+/// reader slots to quiesce.
+///
+/// Retrying immediately after a conflict makes every contending thread
+/// hammer the same cache lines at once. `Backoff` spreads those retries
+/// out: first by spinning for longer and longer, then by yielding the
+/// thread to let other work run, so that the caller can fall back to
+/// blocking via `ControlBlock` once `is_completed()` returns `true`.
+pub struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    /// Create a new `Backoff` with its step counter reset.
+    pub fn new() -> Backoff {
+        Backoff { step: 0 }
+    }
+
+    /// Reset the backoff, so it can be reused for the next retry loop.
+    pub fn reset(&mut self) {
+        self.step = 0;
+    }
+
+    /// Spin for a short, exponentially increasing number of iterations.
+    ///
+    /// Keeps the thread hot, which is cheap as long as the wait is expected
+    /// to be brief, such as a few failed attempts to acquire a commit lock.
+    pub fn spin(&mut self) {
+        for _ in 0..1u32 << self.step.min(SPIN_LIMIT) {
+            ::std::hint::spin_loop();
+        }
+        self.step += 1;
+    }
+
+    /// Spin while the wait is still short, but yield the thread to the
+    /// scheduler once it drags on.
+    ///
+    /// Use this while retrying a whole transaction after a detected
+    /// conflict: once `is_completed()` returns `true`, give up snoozing
+    /// and park the thread via `ControlBlock` instead.
+    pub fn snooze(&mut self) {
+        if self.step <= SPIN_LIMIT {
+            for _ in 0..1u32 << self.step {
+                ::std::hint::spin_loop();
+            }
+        } else {
+            thread::yield_now();
+        }
+        self.step += 1;
+    }
+
+    /// Returns `true` once enough retries have passed that the caller
+    /// should stop spinning/yielding and block instead.
+    pub fn is_completed(&self) -> bool {
+        self.step > YIELD_LIMIT
+    }
+}
+
+// TESTS
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A fresh `Backoff` has not completed yet.
+    #[test]
+    fn starts_incomplete() {
+        let backoff = Backoff::new();
+        assert!(!backoff.is_completed());
+    }
+
+    /// After enough `snooze` calls, the backoff reports completion.
+    #[test]
+    fn completes_after_enough_snoozes() {
+        let mut backoff = Backoff::new();
+        for _ in 0..=YIELD_LIMIT {
+            assert!(!backoff.is_completed());
+            backoff.snooze();
+        }
+        assert!(backoff.is_completed());
+    }
+
+    /// `reset` brings the backoff back to its initial state.
+    #[test]
+    fn reset_restarts_backoff() {
+        let mut backoff = Backoff::new();
+        for _ in 0..=YIELD_LIMIT {
+            backoff.snooze();
+        }
+        assert!(backoff.is_completed());
+
+        backoff.reset();
+        assert!(!backoff.is_completed());
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2015-2016 rust-stm Developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Optional deadlock detection for transactions blocked in `retry`.
+//!
+//! `ControlBlock::wait` can block indefinitely, and a transaction blocks on
+//! its whole read set at once, so it is easy to construct a cycle where
+//! transaction A waits on a `Var` only B will write and vice versa. This
+//! module keeps a global registry of the wait-for graph between blocked
+//! `ControlBlock`s and the `Var`s they are waiting on, and exposes
+//! `check_deadlock` so an application can abort a victim transaction
+//! instead of hanging forever.
+//!
+//! `Var::retry` registers with this graph via `ControlBlock::register_wait`,
+//! and `Var::set_value` claims ownership via `claim_owner` before
+//! publishing, so the graph reflects real blocked transactions rather than
+//! only what tests register directly.
+//!
+//! Disabled by default; enable with the `deadlock_detection` feature.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+use std::thread::{self, ThreadId};
+
+/// Stable identity of a `Var`, handed out once per `Var` and never reused.
+pub type VarId = usize;
+
+/// Stable identity of a `ControlBlock`, handed out once per blocked
+/// transaction and never reused.
+pub type BlockId = usize;
+
+/// Generate the next `VarId`/`BlockId` from a process-wide counter.
+fn next_id(counter: &AtomicUsize) -> usize {
+    counter.fetch_add(1, Ordering::Relaxed)
+}
+
+static NEXT_VAR_ID: AtomicUsize = AtomicUsize::new(0);
+static NEXT_BLOCK_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocate a fresh, process-wide unique `VarId`.
+///
+/// Called once per `Var` on construction.
+pub fn new_var_id() -> VarId {
+    next_id(&NEXT_VAR_ID)
+}
+
+/// Allocate a fresh, process-wide unique `BlockId`.
+///
+/// Called once per `ControlBlock` on construction.
+pub fn new_block_id() -> BlockId {
+    next_id(&NEXT_BLOCK_ID)
+}
+
+/// The global wait-for registry.
+struct Registry {
+    /// Which vars a blocked control block is waiting on, and which thread
+    /// owns that control block.
+    waiting: HashMap<BlockId, (ThreadId, Vec<VarId>)>,
+
+    /// Which thread currently "owns" progress on a var, i.e. the thread
+    /// most recently known to be about to write it.
+    owners: HashMap<VarId, ThreadId>,
+}
+
+impl Registry {
+    fn new() -> Registry {
+        Registry {
+            waiting: HashMap::new(),
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Register that the current thread's control block `block` is now
+    /// blocked waiting on `vars`.
+    fn register(&mut self, block: BlockId, vars: &[VarId]) {
+        self.waiting
+            .insert(block, (thread::current().id(), vars.to_vec()));
+    }
+
+    /// Remove `block` from the wait-for graph.
+    fn deregister(&mut self, block: BlockId) {
+        self.waiting.remove(&block);
+    }
+
+    /// Record that the current thread is about to make progress on `var`.
+    fn claim_owner(&mut self, var: VarId) {
+        self.owners.insert(var, thread::current().id());
+    }
+
+    /// Walk the wait-for graph looking for a cycle.
+    ///
+    /// Returns the `BlockId`s involved in the first cycle found, or an
+    /// empty vector if there is none.
+    fn check_deadlock(&self) -> Vec<BlockId> {
+        // Build thread -> block edges for threads that are themselves
+        // blocked, so we can walk from "thread is blocked on var" to "var
+        // is owned by thread" to "is that thread blocked too?".
+        let mut thread_to_block: HashMap<ThreadId, BlockId> = HashMap::new();
+        for (&block, &(thread, _)) in &self.waiting {
+            thread_to_block.insert(thread, block);
+        }
+
+        for (&start, _) in &self.waiting {
+            let mut path = vec![start];
+            if let Some(cycle) = walk(self, &thread_to_block, start, &mut path) {
+                return cycle;
+            }
+        }
+        Vec::new()
+    }
+}
+
+static REGISTRY_INIT: Once = Once::new();
+static mut REGISTRY_PTR: *const Mutex<Registry> = 0 as *const Mutex<Registry>;
+
+/// Lazily initialize and return the global wait-for registry.
+fn registry() -> &'static Mutex<Registry> {
+    unsafe {
+        REGISTRY_INIT.call_once(|| {
+            REGISTRY_PTR = Box::into_raw(Box::new(Mutex::new(Registry::new())));
+        });
+        &*REGISTRY_PTR
+    }
+}
+
+/// Register that the current thread's control block `block` is now blocked
+/// waiting on `vars`, in the global registry.
+///
+/// Must be paired with a later call to `deregister`, typically via
+/// `ControlBlock::register_wait` and then `ControlBlock::wait`/`wait_deadline`.
+pub fn register(block: BlockId, vars: &[VarId]) {
+    registry().lock().unwrap().register(block, vars);
+}
+
+/// Remove `block` from the global wait-for graph, e.g. once its
+/// transaction has woken up and is no longer blocked.
+pub fn deregister(block: BlockId) {
+    registry().lock().unwrap().deregister(block);
+}
+
+/// Record that the current thread is about to make progress on `var`,
+/// e.g. because it is about to commit a write to it, in the global
+/// registry.
+///
+/// Used as the "owner" of a var in the wait-for graph: a blocked control
+/// block waiting on `var` is considered to be waiting on whichever thread
+/// last called this function for that var.
+pub fn claim_owner(var: VarId) {
+    registry().lock().unwrap().claim_owner(var);
+}
+
+/// Walk the global wait-for graph looking for a cycle.
+///
+/// Returns the `BlockId`s involved in the first cycle found, or an empty
+/// vector if there is none. The caller can use this to abort one of the
+/// involved transactions rather than let them hang forever.
+pub fn check_deadlock() -> Vec<BlockId> {
+    registry().lock().unwrap().check_deadlock()
+}
+
+/// Depth-first search for a cycle reachable from `block`, recorded in `path`.
+fn walk(
+    registry: &Registry,
+    thread_to_block: &HashMap<ThreadId, BlockId>,
+    block: BlockId,
+    path: &mut Vec<BlockId>,
+) -> Option<Vec<BlockId>> {
+    let (_, vars) = registry.waiting.get(&block)?;
+
+    for var in vars {
+        let owner_thread = match registry.owners.get(var) {
+            Some(thread) => thread,
+            // No one has claimed this var yet, so it can't be part of a
+            // cycle through this path; keep checking the block's other vars.
+            None => continue,
+        };
+        let next_block = match thread_to_block.get(owner_thread) {
+            Some(&b) => b,
+            None => continue,
+        };
+
+        if next_block == path[0] {
+            return Some(path.clone());
+        }
+        if path.contains(&next_block) {
+            continue;
+        }
+
+        path.push(next_block);
+        if let Some(cycle) = walk(registry, thread_to_block, next_block, path) {
+            return Some(cycle);
+        }
+        path.pop();
+    }
+
+    None
+}
+
+// TESTS
+//
+// These tests exercise `Registry` directly, each on its own fresh
+// instance, rather than going through `register`/`claim_owner`/
+// `check_deadlock`'s shared global singleton: `cargo test` runs `#[test]`
+// fns on separate threads by default, and two tests sharing one
+// process-wide `Registry` would trip over each other's in-progress
+// `register`/`deregister` pairs.
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// No deadlock is reported when nothing is registered.
+    #[test]
+    fn no_deadlock_when_empty() {
+        let registry = Registry::new();
+        assert!(registry.check_deadlock().is_empty());
+    }
+
+    /// A control block waiting on a var owned by its own thread forms a
+    /// (degenerate) cycle.
+    #[test]
+    fn detects_self_cycle() {
+        // This test exercises the graph walk directly, rather than
+        // spawning real threads, since ownership is keyed by `ThreadId`
+        // and the current thread can only "own" one side of the cycle.
+        let mut registry = Registry::new();
+        let var_a = new_var_id();
+        let block_a = new_block_id();
+
+        registry.claim_owner(var_a);
+        registry.register(block_a, &[var_a]);
+
+        // The current thread both owns `var_a` and is blocked on it,
+        // which is a (degenerate) self-cycle.
+        let cycle = registry.check_deadlock();
+        assert!(cycle.contains(&block_a));
+
+        registry.deregister(block_a);
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright 2015-2016 rust-stm Developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Sharded reader/writer synchronization for the commit path.
+//!
+//! Serializing every validating transaction and every commit through a
+//! single lock limits throughput under many reader threads, since they all
+//! contend on the same cache line. `ShardedRwLock` instead assigns each
+//! reader thread its own slot in an array of cache-line-padded counters, so
+//! entering a read/validation region only bumps that thread's own counter.
+//! A committing writer flips a single shared flag and then waits for every
+//! reader slot to quiesce before publishing its writes.
+//!
+//! `global()` returns the single instance shared by every `Var`.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, Once};
+
+use super::backoff::Backoff;
+
+/// Maximum number of reader threads that can hold a slot at once.
+const NUM_SHARDS: usize = 64;
+
+/// Pads `T` out to a full cache line, so that two different reader slots
+/// never sit on the same line and false-share under concurrent updates.
+#[repr(align(64))]
+pub struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+    fn new(value: T) -> CachePadded<T> {
+        CachePadded { value }
+    }
+}
+
+impl<T> ::std::ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// Whether each of the `NUM_SHARDS` slots is currently leased to a thread.
+struct SlotTable {
+    taken: Vec<AtomicBool>,
+}
+
+static SLOT_TABLE_INIT: Once = Once::new();
+static mut SLOT_TABLE_PTR: *const SlotTable = 0 as *const SlotTable;
+
+fn slot_table() -> &'static SlotTable {
+    unsafe {
+        SLOT_TABLE_INIT.call_once(|| {
+            let taken = (0..NUM_SHARDS).map(|_| AtomicBool::new(false)).collect();
+            SLOT_TABLE_PTR = Box::into_raw(Box::new(SlotTable { taken }));
+        });
+        &*SLOT_TABLE_PTR
+    }
+}
+
+/// A leased shard slot, released back to the table when the owning thread
+/// is done with it (typically at thread exit, via the `thread_local`
+/// destructor).
+struct SlotLease(usize);
+
+impl Drop for SlotLease {
+    fn drop(&mut self) {
+        slot_table().taken[self.0].store(false, Ordering::Release);
+    }
+}
+
+thread_local! {
+    static SHARD_SLOT: RefCell<Option<SlotLease>> = RefCell::new(None);
+}
+
+/// Return a stable shard index for the current thread, leasing a fresh
+/// slot the first time the thread is seen and holding it for the thread's
+/// lifetime.
+///
+/// Unlike a `NEXT_SHARD.fetch_add(..) % NUM_SHARDS` counter, which would
+/// hand two different threads the same slot once more than `NUM_SHARDS`
+/// threads have ever called this function, a lease is only reused once its
+/// previous owner thread has exited and released it, so two threads never
+/// share a slot concurrently. Panics if every slot is leased at once,
+/// rather than silently letting two threads collide.
+fn shard_for_current_thread() -> usize {
+    SHARD_SLOT.with(|slot| {
+        let mut slot = slot.borrow_mut();
+        if let Some(lease) = slot.as_ref() {
+            return lease.0;
+        }
+
+        let table = slot_table();
+        for (index, taken) in table.taken.iter().enumerate() {
+            if taken
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                *slot = Some(SlotLease(index));
+                return index;
+            }
+        }
+
+        panic!(
+            "ShardedRwLock: more than {} reader threads active at once",
+            NUM_SHARDS
+        );
+    })
+}
+
+/// A readers-writer mechanism tuned for many concurrent readers and rare
+/// writers, used to guard commit validation.
+///
+/// Entering a read region (`enter_read`) only touches the calling thread's
+/// own counter, so concurrent readers never contend on one atomic.
+/// Committing a write (`begin_write`) takes an internal mutex that
+/// serializes writers against each other, flips a single shared flag, and
+/// waits for every slot to show no reader in flight; `enter_read` itself
+/// also checks that flag, so a reader can't slip in between a writer
+/// raising it and the writer finishing its quiescence check.
+pub struct ShardedRwLock {
+    readers: Vec<CachePadded<AtomicUsize>>,
+    writer_active: AtomicBool,
+
+    /// Serializes `begin_write` callers against each other. `writer_active`
+    /// alone cannot do this: two threads both storing `true` into it race
+    /// just as easily as two threads both storing `false`, so without this
+    /// mutex one writer's commit can finish and clear the flag while
+    /// another writer is still mid-commit, letting a reader's `enter_read`
+    /// slip in through the resulting gap.
+    commit_lock: Mutex<()>,
+}
+
+impl ShardedRwLock {
+    /// Create a new, unlocked `ShardedRwLock`.
+    pub fn new() -> ShardedRwLock {
+        let readers = (0..NUM_SHARDS)
+            .map(|_| CachePadded::new(AtomicUsize::new(0)))
+            .collect();
+        ShardedRwLock {
+            readers,
+            writer_active: AtomicBool::new(false),
+            commit_lock: Mutex::new(()),
+        }
+    }
+
+    /// Enter a read/validation region on the current thread.
+    ///
+    /// The returned `ReadGuard` marks the region as over when dropped.
+    pub fn enter_read(&self) -> ReadGuard<'_> {
+        let slot = shard_for_current_thread();
+        let mut backoff = Backoff::new();
+        loop {
+            while self.writer_active.load(Ordering::Acquire) {
+                backoff.spin();
+            }
+
+            // Odd counter value means "a reader is currently in this slot".
+            self.readers[slot].fetch_add(1, Ordering::AcqRel);
+
+            if !self.writer_active.load(Ordering::Acquire) {
+                break;
+            }
+
+            // A writer raised the flag between our check and our fetch_add
+            // above; back out (restoring an even count) and retry, so
+            // `begin_write`'s quiescence check can't miss us.
+            self.readers[slot].fetch_add(1, Ordering::AcqRel);
+        }
+        ReadGuard { lock: self, slot }
+    }
+
+    /// Begin a commit: claim the commit mutex (excluding any other
+    /// concurrent writer), raise the writer flag, and wait for every
+    /// reader slot to quiesce.
+    ///
+    /// Returns a `WriteGuard` that releases the mutex and clears the
+    /// writer flag when dropped, ending the commit.
+    pub fn begin_write(&self) -> WriteGuard<'_> {
+        let commit_guard = self.commit_lock.lock().unwrap();
+        self.writer_active.store(true, Ordering::SeqCst);
+        for slot in &self.readers {
+            let mut backoff = Backoff::new();
+            while slot.load(Ordering::Acquire) % 2 == 1 {
+                backoff.spin();
+            }
+        }
+        WriteGuard {
+            lock: self,
+            _commit_guard: commit_guard,
+        }
+    }
+}
+
+/// Marks an in-progress read/validation region on one reader slot.
+pub struct ReadGuard<'a> {
+    lock: &'a ShardedRwLock,
+    slot: usize,
+}
+
+impl<'a> Drop for ReadGuard<'a> {
+    fn drop(&mut self) {
+        // Back to an even counter value: no reader in flight on this slot.
+        self.lock.readers[self.slot].fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Marks an in-progress commit, holding the writer flag and the commit
+/// mutex until dropped.
+pub struct WriteGuard<'a> {
+    lock: &'a ShardedRwLock,
+    _commit_guard: MutexGuard<'a, ()>,
+}
+
+impl<'a> Drop for WriteGuard<'a> {
+    fn drop(&mut self) {
+        self.lock.writer_active.store(false, Ordering::SeqCst);
+    }
+}
+
+static GLOBAL_INIT: Once = Once::new();
+static mut GLOBAL_PTR: *const ShardedRwLock = 0 as *const ShardedRwLock;
+
+/// The single `ShardedRwLock` shared by every `Var`'s reads and writes.
+pub fn global() -> &'static ShardedRwLock {
+    unsafe {
+        GLOBAL_INIT.call_once(|| {
+            GLOBAL_PTR = Box::into_raw(Box::new(ShardedRwLock::new()));
+        });
+        &*GLOBAL_PTR
+    }
+}
+
+// TESTS
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// A reader region alone does not block a writer from starting once
+    /// the guard has been dropped.
+    #[test]
+    fn write_proceeds_after_read_ends() {
+        let lock = ShardedRwLock::new();
+        {
+            let _guard = lock.enter_read();
+        }
+        let _write_guard = lock.begin_write();
+    }
+
+    /// Different calls within the same thread reuse the same shard slot.
+    #[test]
+    fn same_thread_reuses_slot() {
+        let lock = ShardedRwLock::new();
+        let slot_a = {
+            let guard = lock.enter_read();
+            guard.slot
+        };
+        let slot_b = {
+            let guard = lock.enter_read();
+            guard.slot
+        };
+        assert_eq!(slot_a, slot_b);
+    }
+
+    /// A writer must not observe quiescence while a reader is still
+    /// holding its guard open on another thread.
+    #[test]
+    fn write_waits_for_concurrent_reader() {
+        use std::time::{Duration, Instant};
+
+        let lock = Arc::new(ShardedRwLock::new());
+        let lock2 = lock.clone();
+        let reader_entered = Arc::new(AtomicUsize::new(0));
+        let reader_entered2 = reader_entered.clone();
+
+        let reader = thread::spawn(move || {
+            let _guard = lock2.enter_read();
+            reader_entered2.store(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            // `_guard` is dropped here, releasing the slot.
+        });
+
+        while reader_entered.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+
+        let start = Instant::now();
+        let _write_guard = lock.begin_write();
+
+        // `begin_write` must have waited for the reader's guard to drop,
+        // not returned the instant it saw the reader was "entering".
+        assert!(start.elapsed() >= Duration::from_millis(80));
+        reader.join().unwrap();
+    }
+
+    /// Two concurrent `begin_write` callers must be serialized: the second
+    /// one only completes its quiescence check (and sees its own
+    /// `writer_active` window) after the first has dropped its guard.
+    #[test]
+    fn concurrent_writers_are_serialized() {
+        use std::sync::atomic::AtomicBool;
+        use std::time::{Duration, Instant};
+
+        let lock = Arc::new(ShardedRwLock::new());
+        let lock2 = lock.clone();
+        let first_writer_active = Arc::new(AtomicBool::new(false));
+        let first_writer_active2 = first_writer_active.clone();
+
+        let writer = thread::spawn(move || {
+            let _guard = lock2.begin_write();
+            first_writer_active2.store(true, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            first_writer_active2.store(false, Ordering::SeqCst);
+            // `_guard` is dropped here.
+        });
+
+        while !first_writer_active.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+
+        let start = Instant::now();
+        let _second_guard = lock.begin_write();
+
+        // The second `begin_write` must not have returned while the first
+        // writer's guard was still held.
+        assert!(!first_writer_active.load(Ordering::SeqCst));
+        assert!(start.elapsed() >= Duration::from_millis(80));
+        writer.join().unwrap();
+    }
+}
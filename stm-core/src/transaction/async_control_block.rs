@@ -0,0 +1,171 @@
+// Copyright 2015-2016 rust-stm Developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+
+/// The async counterpart to `ControlBlock`.
+///
+/// STM blocks on all read variables if retry was called.
+/// Where `ControlBlock` parks the calling OS thread, `AsyncControlBlock`
+/// registers the polling task's `Waker` instead, so that a blocked
+/// transaction suspends its `Future` rather than occupying a whole thread.
+///
+/// Be careful when using this directly,
+/// because you can easily create deadlocks.
+pub struct AsyncControlBlock {
+    /// Atomic bool stores if the task has been blocked yet.
+    /// Make sure that the waker is registered again if no change
+    /// has happened since the last poll.
+    blocked: AtomicBool,
+
+    /// The waker of the task that is currently polling `wait`.
+    ///
+    /// Unlike the `Thread` handle in `ControlBlock`, this can safely be
+    /// replaced by whichever task last polled the future, so
+    /// `set_changed` always wakes the right one.
+    waker: Mutex<Option<Waker>>,
+}
+
+impl AsyncControlBlock {
+    #[cfg_attr(feature = "cargo-clippy", allow(new_without_default_derive))]
+
+    /// Create a new `AsyncControlBlock`.
+    pub fn new() -> AsyncControlBlock {
+        AsyncControlBlock {
+            blocked: AtomicBool::new(true),
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Inform the control block that a variable has changed.
+    ///
+    /// Need to be called from outside of STM.
+    pub fn set_changed(&self) {
+        // Only wakeup once.
+        if self.blocked.swap(false, Ordering::SeqCst) {
+            if let Some(waker) = self.waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Block until one variable has changed.
+    ///
+    /// `wait` returns a `Future` that may resolve immediately.
+    ///
+    /// The returned future needs to be polled by the STM instance itself.
+    pub fn wait(&self) -> Wait<'_> {
+        Wait { ctrl: self }
+    }
+}
+
+/// Future returned by `AsyncControlBlock::wait`.
+///
+/// Resolves once the `AsyncControlBlock` has been set to changed.
+pub struct Wait<'a> {
+    ctrl: &'a AsyncControlBlock,
+}
+
+impl<'a> Future for Wait<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.ctrl.blocked.load(Ordering::SeqCst) {
+            // Register our waker so `set_changed` can wake us up,
+            // then check again in case a change raced in before
+            // registration.
+            *self.ctrl.waker.lock().unwrap() = Some(cx.waker().clone());
+
+            if self.ctrl.blocked.load(Ordering::SeqCst) {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+// TESTS
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// A minimal no-op waker, good enough to drive a single poll by hand
+    /// without pulling in an executor.
+    fn noop_waker() -> Waker {
+        use std::task::{RawWaker, RawWakerVTable};
+
+        fn clone(_: *const ()) -> RawWaker {
+            raw_waker()
+        }
+        fn wake(_: *const ()) {}
+        fn wake_by_ref(_: *const ()) {}
+        fn drop(_: *const ()) {}
+
+        fn raw_waker() -> RawWaker {
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+
+        unsafe { Waker::from_raw(raw_waker()) }
+    }
+
+    fn poll_once(fut: &mut Wait<'_>) -> Poll<()> {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        Pin::new(fut).poll(&mut cx)
+    }
+
+    /// Test that `wait` stays pending until `set_changed` is called.
+    #[test]
+    fn pending_until_changed() {
+        let ctrl = AsyncControlBlock::new();
+        let mut fut = ctrl.wait();
+        assert_eq!(poll_once(&mut fut), Poll::Pending);
+
+        ctrl.set_changed();
+        assert_eq!(poll_once(&mut fut), Poll::Ready(()));
+    }
+
+    /// A `wait` future started after `set_changed` resolves immediately.
+    #[test]
+    fn ready_if_already_changed() {
+        let ctrl = AsyncControlBlock::new();
+        ctrl.set_changed();
+
+        let mut fut = ctrl.wait();
+        assert_eq!(poll_once(&mut fut), Poll::Ready(()));
+    }
+
+    /// Waking from another thread must resolve the future.
+    #[test]
+    fn wait_threaded_wakeup() {
+        let ctrl = Arc::new(AsyncControlBlock::new());
+        let ctrl2 = ctrl.clone();
+
+        let mut fut = ctrl.wait();
+        assert_eq!(poll_once(&mut fut), Poll::Pending);
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            ctrl2.set_changed();
+        });
+        handle.join().unwrap();
+
+        assert_eq!(poll_once(&mut fut), Poll::Ready(()));
+    }
+}
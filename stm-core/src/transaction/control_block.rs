@@ -8,11 +8,24 @@
 
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::{self, Thread};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "deadlock_detection")]
+use super::super::deadlock::{self, BlockId, VarId};
 
 #[cfg(test)]
 use super::super::test::{terminates, terminates_async};
 
+/// Outcome of `ControlBlock::wait_deadline`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    /// A variable changed before the deadline elapsed.
+    Changed,
+
+    /// The deadline elapsed before any variable changed.
+    TimedOut,
+}
+
 /// A control block for a currently running STM instance.
 ///
 /// STM blocks on all read variables if retry was called.
@@ -30,6 +43,11 @@ pub struct ControlBlock {
 
     // Safety check to avoid deadlocks.
     park_timeout: Duration,
+
+    /// Stable id used to register this control block in the global
+    /// deadlock-detection wait-for graph.
+    #[cfg(feature = "deadlock_detection")]
+    id: BlockId,
 }
 
 impl ControlBlock {
@@ -41,6 +59,8 @@ impl ControlBlock {
             thread: thread::current(),
             blocked: AtomicBool::new(true),
             park_timeout: Duration::from_millis(1000),
+            #[cfg(feature = "deadlock_detection")]
+            id: deadlock::new_block_id(),
         }
     }
 
@@ -55,6 +75,17 @@ impl ControlBlock {
         }
     }
 
+    /// Register this control block as blocked on `vars` in the global
+    /// deadlock-detection registry.
+    ///
+    /// Only available with the `deadlock_detection` feature. Must be
+    /// followed by a call to `deadlock::deregister` once the transaction
+    /// wakes up or gives up, which `wait` and `wait_deadline` take care of.
+    #[cfg(feature = "deadlock_detection")]
+    pub fn register_wait(&self, vars: &[VarId]) {
+        deadlock::register(self.id, vars);
+    }
+
     /// Block until one variable has changed.
     ///
     /// `wait` may immediately return.
@@ -72,6 +103,43 @@ impl ControlBlock {
             // To deal with both, make sure the thread is not parked forever.
             thread::park_timeout(self.park_timeout);
         }
+
+        #[cfg(feature = "deadlock_detection")]
+        deadlock::deregister(self.id);
+    }
+
+    /// Block until one variable has changed, or `deadline` passes,
+    /// whichever comes first.
+    ///
+    /// Unlike `wait`, which only uses `park_timeout` as a safety net to
+    /// re-check `blocked`, this reports back via `WaitResult` whether it
+    /// returned because a variable changed or because the deadline
+    /// elapsed, so `retry_timeout`/`atomically_or_timeout` can implement
+    /// bounded blocking instead of blocking forever.
+    pub fn wait_deadline(&self, deadline: Instant) -> WaitResult {
+        while self.blocked.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                #[cfg(feature = "deadlock_detection")]
+                deadlock::deregister(self.id);
+                return WaitResult::TimedOut;
+            }
+
+            // Never park past the deadline, and never past our usual
+            // safety-net timeout either.
+            let remaining = deadline - now;
+            let park_for = if remaining < self.park_timeout {
+                remaining
+            } else {
+                self.park_timeout
+            };
+            thread::park_timeout(park_for);
+        }
+
+        #[cfg(feature = "deadlock_detection")]
+        deadlock::deregister(self.id);
+
+        WaitResult::Changed
     }
 
     /// Here to make tests faster while allowing a long timeout in the normal case.
@@ -150,4 +218,21 @@ mod test {
 
         assert!(terminated);
     }
+
+    /// `wait_deadline` reports `TimedOut` when nothing changes in time.
+    #[test]
+    fn wait_deadline_times_out() {
+        let ctrl = ControlBlock::new();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(ctrl.wait_deadline(deadline), WaitResult::TimedOut);
+    }
+
+    /// `wait_deadline` reports `Changed` when woken up before the deadline.
+    #[test]
+    fn wait_deadline_reports_change() {
+        let ctrl = ControlBlock::new();
+        ctrl.set_changed();
+        let deadline = Instant::now() + Duration::from_millis(500);
+        assert_eq!(ctrl.wait_deadline(deadline), WaitResult::Changed);
+    }
 }
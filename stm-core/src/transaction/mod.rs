@@ -0,0 +1,13 @@
+// Copyright 2015-2016 rust-stm Developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+mod control_block;
+mod async_control_block;
+
+pub use self::control_block::{ControlBlock, WaitResult};
+pub use self::async_control_block::AsyncControlBlock;